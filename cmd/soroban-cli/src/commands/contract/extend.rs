@@ -1,12 +1,14 @@
-use std::{fmt::Debug, path::Path, str::FromStr};
+use std::{fmt::Debug, path::Path, path::PathBuf, str::FromStr};
 
 use clap::{command, Parser};
 use soroban_env_host::xdr::{
-    Error as XdrError, ExtendFootprintTtlOp, ExtensionPoint, LedgerEntry, LedgerEntryChange,
-    LedgerEntryData, LedgerFootprint, Memo, MuxedAccount, Operation, OperationBody, Preconditions,
-    SequenceNumber, SorobanResources, SorobanTransactionData, Transaction, TransactionExt,
-    TransactionMeta, TransactionMetaV3, TtlEntry, Uint256,
+    AccountEntry, AccountId, Error as XdrError, ExtendFootprintTtlOp, ExtensionPoint, Limits,
+    LedgerEntryData, LedgerFootprint, LedgerKey, Memo, MuxedAccount, Operation, OperationBody,
+    Preconditions, PublicKey, SequenceNumber, SorobanResources, SorobanTransactionData,
+    Transaction, TransactionExt, TransactionMeta, TransactionMetaV3, Uint256, WriteXdr,
 };
+use soroban_ledger_snapshot::LedgerSnapshot;
+use soroban_simulation::{simulation::simulate_and_assemble_transaction, AppNetworkInfo};
 
 use crate::{
     commands::config,
@@ -26,6 +28,22 @@ pub struct Cmd {
     /// Only print the new Time To Live ledger
     #[arg(long)]
     pub ttl_ledger_only: bool,
+    /// If any key is archived, restore it before extending
+    #[arg(long)]
+    pub restore_if_needed: bool,
+    /// Additional instructions to pad the simulated resources with, to avoid
+    /// `txInsufficientResourceFee` on large footprints whose real cost the preflight
+    /// estimate undershoots
+    #[arg(long)]
+    pub instruction_leeway: Option<u64>,
+    /// Compute the footprint/resources locally from a ledger snapshot instead of asking a
+    /// live RPC server to preflight the transaction, and print the assembled transaction XDR
+    /// instead of submitting it. Useful for air-gapped or deterministic CI setups.
+    #[arg(long, requires = "ledger_snapshot")]
+    pub sim_only: bool,
+    /// Ledger snapshot to read entries and network settings from when using `--sim-only`
+    #[arg(long)]
+    pub ledger_snapshot: Option<PathBuf>,
     #[command(flatten)]
     pub key: key::Args,
     #[command(flatten)]
@@ -69,22 +87,56 @@ pub enum Error {
     LedgerEntryNotFound,
     #[error("missing operation result")]
     MissingOperationResult,
+    #[error("entries have already been archived, restore them before extending (e.g. with --restore-if-needed): {keys:?}")]
+    EntryArchived { keys: Vec<LedgerKey> },
     #[error(transparent)]
     Rpc(#[from] rpc::Error),
     #[error(transparent)]
     Wasm(#[from] wasm::Error),
     #[error(transparent)]
     Key(#[from] key::Error),
+    #[error(transparent)]
+    Restore(#[from] super::restore::Error),
+    #[error("reading ledger snapshot {path}: {error}")]
+    CannotReadLedgerSnapshot {
+        path: PathBuf,
+        error: soroban_ledger_snapshot::Error,
+    },
+    #[error(transparent)]
+    Simulation(#[from] soroban_simulation::Error),
+    #[error("source account not found in ledger snapshot")]
+    SourceAccountNotInSnapshot,
+    #[error("--ledger-snapshot is required alongside --sim-only")]
+    LedgerSnapshotRequired,
+}
+
+/// The new `live_until_ledger_seq` for a single extended key, paired with the key itself so
+/// callers with more than one `--key`/`--key-xdr` can tell which result belongs to which entry.
+pub struct ExtendedKey {
+    pub key: LedgerKey,
+    pub live_until_ledger_seq: u32,
 }
 
 impl Cmd {
     #[allow(clippy::too_many_lines)]
     pub async fn run(&self) -> Result<(), Error> {
-        let ttl_ledger = self.run_against_rpc_server().await?;
-        if self.ttl_ledger_only {
-            println!("{ttl_ledger}");
-        } else {
-            println!("New ttl ledger: {ttl_ledger}");
+        if self.sim_only {
+            let tx = self.run_sim_only().await?;
+            println!("{}", tx.to_xdr_base64(Limits::none())?);
+            return Ok(());
+        }
+
+        let extensions = self.run_against_rpc_server().await?;
+        for extended in &extensions {
+            if self.ttl_ledger_only {
+                println!("{}", extended.live_until_ledger_seq);
+            } else {
+                println!(
+                    "New ttl ledger for {}: {}",
+                    extended.key.to_xdr_base64(Limits::none())?,
+                    extended.live_until_ledger_seq,
+                );
+            }
         }
 
         Ok(())
@@ -100,7 +152,32 @@ impl Cmd {
         res
     }
 
-    async fn run_against_rpc_server(&self) -> Result<u32, Error> {
+    async fn run_against_rpc_server(&self) -> Result<Vec<ExtendedKey>, Error> {
+        match self.try_extend().await {
+            Err(Error::EntryArchived { keys }) if self.restore_if_needed => {
+                self.restore_archived_keys(&keys).await?;
+                self.try_extend().await
+            }
+            result => result,
+        }
+    }
+
+    async fn restore_archived_keys(&self, keys: &[LedgerKey]) -> Result<(), Error> {
+        tracing::info!("restoring {} archived entries before extending", keys.len());
+        let restore = super::restore::Cmd {
+            ttl_ledger_only: true,
+            key: self.key.clone(),
+            config: self.config.clone(),
+            fee: self.fee.clone(),
+        };
+        // Use the non-printing half of restore's implementation: `Cmd::run` prints its own ttl
+        // line as a side effect, which would corrupt `extend --ttl-ledger-only`'s stdout. Pass
+        // only the keys that are actually archived, not the full `--key`/`--key-xdr` list.
+        restore.restore_keys(keys).await?;
+        Ok(())
+    }
+
+    async fn try_extend(&self) -> Result<Vec<ExtendedKey>, Error> {
         let network = self.config.get_network()?;
         tracing::trace!(?network);
         let keys = self.key.parse_keys()?;
@@ -145,7 +222,15 @@ impl Cmd {
         };
 
         let (result, meta, events) = client
-            .prepare_and_send_transaction(&tx, &key, &[], &network.network_passphrase, None, None)
+            .prepare_and_send_transaction(
+                &tx,
+                &key,
+                &[],
+                &network.network_passphrase,
+                None,
+                None,
+                self.instruction_leeway,
+            )
             .await?;
 
         tracing::trace!(?result);
@@ -155,38 +240,197 @@ impl Cmd {
         }
 
         // The transaction from core will succeed regardless of whether it actually found & extended
-        // the entry, so we have to inspect the result meta to tell if it worked or not.
+        // the entries, so we have to inspect the result meta to tell if it worked or not.
         let TransactionMeta::V3(TransactionMetaV3 { operations, .. }) = meta else {
             return Err(Error::LedgerEntryNotFound);
         };
 
-        // Simply check if there is exactly one entry here. We only support extending a single
-        // entry via this command (which we should fix separately, but).
-        if operations.len() == 0 {
+        if operations.is_empty() {
             return Err(Error::LedgerEntryNotFound);
         }
 
-        if operations[0].changes.is_empty() {
-            let entry = client.get_full_ledger_entries(&keys).await?;
-            let extension = entry.entries[0].live_until_ledger_seq;
-            if entry.latest_ledger + i64::from(extend_to) < i64::from(extension) {
-                return Ok(extension);
+        // A key that was already past `extend_to` produces no change pair at all, so we can't
+        // assume one (State, Updated) pair per key in footprint order; match by the ttl entry's
+        // key hash instead, and fall back to reading the rest directly to report their current
+        // ttl (and to catch any that are actually archived).
+        let changed = super::restore::ttl_changes_by_key_hash(&operations[0].changes)
+            .ok_or(Error::LedgerEntryNotFound)?;
+
+        let mut extensions = Vec::with_capacity(keys.len());
+        let mut unchanged_keys = Vec::new();
+        for key in &keys {
+            match changed.get(&super::restore::ttl_key_hash(key)?) {
+                Some(live_until_ledger_seq) => extensions.push(ExtendedKey {
+                    key: key.clone(),
+                    live_until_ledger_seq: *live_until_ledger_seq,
+                }),
+                None => unchanged_keys.push(key.clone()),
             }
         }
 
-        match (&operations[0].changes[0], &operations[0].changes[1]) {
-            (
-                LedgerEntryChange::State(_),
-                LedgerEntryChange::Updated(LedgerEntry {
-                    data:
-                        LedgerEntryData::Ttl(TtlEntry {
-                            live_until_ledger_seq,
-                            ..
-                        }),
-                    ..
+        if !unchanged_keys.is_empty() {
+            extensions.extend(
+                self.get_unchanged_extensions(&client, &unchanged_keys, extend_to)
+                    .await?,
+            );
+        }
+
+        Ok(extensions)
+    }
+
+    /// Assemble the extend transaction entirely from a local ledger snapshot, without ever
+    /// talking to an RPC server. Returns the fully assembled transaction, resources and
+    /// resource fee included, ready to be signed and submitted out-of-band.
+    async fn run_sim_only(&self) -> Result<Transaction, Error> {
+        let snapshot_path = self
+            .ledger_snapshot
+            .as_ref()
+            .ok_or(Error::LedgerSnapshotRequired)?;
+        let keys = self.key.parse_keys()?;
+        let snapshot =
+            LedgerSnapshot::read_file(snapshot_path).map_err(|error| Error::CannotReadLedgerSnapshot {
+                path: snapshot_path.clone(),
+                error,
+            })?;
+        let key = self.config.key_pair()?;
+        let extend_to = self.ledgers_to_extend();
+        let account_id = AccountId(PublicKey::PublicKeyTypeEd25519(Uint256(
+            key.verifying_key().to_bytes(),
+        )));
+        let sequence = Self::source_account_sequence(&snapshot, &account_id)?;
+
+        let tx = Transaction {
+            source_account: MuxedAccount::Ed25519(Uint256(key.verifying_key().to_bytes())),
+            fee: self.fee.fee,
+            seq_num: SequenceNumber(sequence + 1),
+            cond: Preconditions::None,
+            memo: Memo::None,
+            operations: vec![Operation {
+                source_account: None,
+                body: OperationBody::ExtendFootprintTtl(ExtendFootprintTtlOp {
+                    ext: ExtensionPoint::V0,
+                    extend_to,
                 }),
-            ) => Ok(*live_until_ledger_seq),
-            _ => Err(Error::LedgerEntryNotFound),
+            }]
+            .try_into()?,
+            ext: TransactionExt::V1(SorobanTransactionData {
+                ext: ExtensionPoint::V0,
+                resources: SorobanResources {
+                    footprint: LedgerFootprint {
+                        read_only: keys.clone().try_into()?,
+                        read_write: vec![].try_into()?,
+                    },
+                    instructions: 0,
+                    read_bytes: 0,
+                    write_bytes: 0,
+                },
+                resource_fee: 0,
+            }),
+        };
+
+        let assembled = simulate_and_assemble_transaction(
+            &snapshot,
+            &AppNetworkInfo {
+                network_passphrase: snapshot.network_passphrase().to_string(),
+            },
+            &tx,
+            self.instruction_leeway,
+        )?;
+
+        Ok(assembled)
+    }
+
+    /// The source account's sequence number, read out of its account entry in the snapshot
+    /// (not to be confused with the ledger's own sequence number, which is an unrelated
+    /// counter). Mirrors what `client.get_account` returns on the live-RPC path.
+    fn source_account_sequence(
+        snapshot: &LedgerSnapshot,
+        account_id: &AccountId,
+    ) -> Result<i64, Error> {
+        snapshot
+            .ledger_entries
+            .iter()
+            .find_map(|(ledger_key, entry)| match (ledger_key, &entry.data) {
+                (
+                    LedgerKey::Account(_),
+                    LedgerEntryData::Account(AccountEntry {
+                        account_id: entry_account_id,
+                        seq_num,
+                        ..
+                    }),
+                ) if entry_account_id == account_id => Some(i64::from(*seq_num)),
+                _ => None,
+            })
+            .ok_or(Error::SourceAccountNotInSnapshot)
+    }
+
+    async fn get_unchanged_extensions(
+        &self,
+        client: &Client,
+        keys: &[LedgerKey],
+        extend_to: u32,
+    ) -> Result<Vec<ExtendedKey>, Error> {
+        let entries = client.get_full_ledger_entries(keys).await?;
+        if entries.entries.len() != keys.len() {
+            // core didn't return an entry for every key we asked about (e.g. a key that was
+            // never archived, just never created); don't silently drop it from the results.
+            return Err(Error::LedgerEntryNotFound);
         }
+        let mut extensions = Vec::with_capacity(keys.len());
+        let mut archived_keys = Vec::new();
+        for (key, entry) in keys.iter().zip(entries.entries.iter()) {
+            if i64::from(entry.live_until_ledger_seq) < entries.latest_ledger {
+                // The entry exists but its ttl has already lapsed: core archived it, and it
+                // cannot be extended again until it's restored. Keep scanning so a caller
+                // retrying with --restore-if-needed learns about every archived key at once,
+                // not just the first one found.
+                archived_keys.push(key.clone());
+            } else if entries.latest_ledger + i64::from(extend_to) < i64::from(entry.live_until_ledger_seq)
+            {
+                extensions.push(ExtendedKey {
+                    key: key.clone(),
+                    live_until_ledger_seq: entry.live_until_ledger_seq,
+                });
+            } else {
+                return Err(Error::LedgerEntryNotFound);
+            }
+        }
+
+        if !archived_keys.is_empty() {
+            return Err(Error::EntryArchived {
+                keys: archived_keys,
+            });
+        }
+
+        Ok(extensions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_instruction_leeway() {
+        let cmd = Cmd::from_str("extend --ledgers-to-extend 100 --instruction-leeway 500").unwrap();
+        assert_eq!(cmd.instruction_leeway, Some(500));
+    }
+
+    #[test]
+    fn instruction_leeway_defaults_to_none() {
+        let cmd = Cmd::from_str("extend --ledgers-to-extend 100").unwrap();
+        assert_eq!(cmd.instruction_leeway, None);
+    }
+
+    #[tokio::test]
+    async fn sim_only_requires_ledger_snapshot() {
+        // clap's `requires = "ledger_snapshot"` keeps this combination out of real CLI usage,
+        // but `Cmd` can still be constructed this way directly (e.g. by a library caller).
+        let mut cmd = Cmd::from_str("extend --ledgers-to-extend 100").unwrap();
+        cmd.sim_only = true;
+        cmd.ledger_snapshot = None;
+
+        let err = cmd.run_sim_only().await.unwrap_err();
+        assert!(matches!(err, Error::LedgerSnapshotRequired));
     }
 }
@@ -0,0 +1,311 @@
+use std::{collections::HashMap, fmt::Debug, path::Path, str::FromStr};
+
+use clap::{command, Parser};
+use sha2::{Digest, Sha256};
+use soroban_env_host::xdr::{
+    Error as XdrError, ExtensionPoint, Hash, LedgerEntry, LedgerEntryChange, LedgerEntryData,
+    LedgerFootprint, LedgerKey, Limits, Memo, MuxedAccount, Operation, OperationBody,
+    Preconditions, RestoreFootprintOp, SequenceNumber, SorobanResources, SorobanTransactionData,
+    Transaction, TransactionExt, TransactionMeta, TransactionMetaV3, TtlEntry, Uint256, WriteXdr,
+};
+
+use crate::{
+    commands::config,
+    key,
+    rpc::{self, Client},
+    wasm, Pwd,
+};
+
+/// The hash a `RestoreFootprint`/`ExtendFootprintTtl` operation's resulting `TtlEntry` is keyed
+/// by, i.e. the hash of the XDR-encoded ledger key the ttl belongs to.
+pub(crate) fn ttl_key_hash(key: &LedgerKey) -> Result<Hash, XdrError> {
+    Ok(Hash(Sha256::digest(key.to_xdr(Limits::none())?).into()))
+}
+
+/// Groups the `(State, Updated(Ttl))` change pairs a `RestoreFootprint`/`ExtendFootprintTtl`
+/// operation records by the hash of the ledger key each pair's ttl entry belongs to.
+///
+/// Core only emits a change pair for entries it actually touched: if even one key in a batch
+/// was already in the desired state (already live, or already past the requested `extend_to`),
+/// the whole batch produces fewer than `2 * keys.len()` changes, with no placeholder for the
+/// untouched key. Callers must look keys up in the returned map rather than assume one pair per
+/// requested key in footprint order. Returns `None` if any pair of changes (or a trailing
+/// unpaired change) is not a `(State, Updated(Ttl))` pair.
+pub(crate) fn ttl_changes_by_key_hash(changes: &[LedgerEntryChange]) -> Option<HashMap<Hash, u32>> {
+    let mut by_hash = HashMap::new();
+    let mut changes = changes.iter();
+    loop {
+        match (changes.next(), changes.next()) {
+            (None, _) => return Some(by_hash),
+            (
+                Some(LedgerEntryChange::State(_)),
+                Some(LedgerEntryChange::Updated(LedgerEntry {
+                    data:
+                        LedgerEntryData::Ttl(TtlEntry {
+                            key_hash,
+                            live_until_ledger_seq,
+                        }),
+                    ..
+                })),
+            ) => {
+                by_hash.insert(key_hash.clone(), *live_until_ledger_seq);
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+#[group(skip)]
+pub struct Cmd {
+    /// Only print the new Time To Live ledger
+    #[arg(long)]
+    pub ttl_ledger_only: bool,
+    #[command(flatten)]
+    pub key: key::Args,
+    #[command(flatten)]
+    pub config: config::Args,
+    #[command(flatten)]
+    pub fee: crate::fee::Args,
+}
+
+impl FromStr for Cmd {
+    type Err = clap::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use clap::{CommandFactory, FromArgMatches};
+        Self::from_arg_matches_mut(&mut Self::command().get_matches_from(s.split_whitespace()))
+    }
+}
+
+impl Pwd for Cmd {
+    fn set_pwd(&mut self, pwd: &Path) {
+        self.config.set_pwd(pwd);
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("parsing key {key}: {error}")]
+    CannotParseKey {
+        key: String,
+        error: soroban_spec_tools::Error,
+    },
+    #[error("parsing XDR key {key}: {error}")]
+    CannotParseXdrKey { key: String, error: XdrError },
+
+    #[error(transparent)]
+    Config(#[from] config::Error),
+    #[error("either `--key` or `--key-xdr` are required")]
+    KeyIsRequired,
+    #[error("xdr processing error: {0}")]
+    Xdr(#[from] XdrError),
+    #[error("Ledger entry not found")]
+    LedgerEntryNotFound,
+    #[error("missing operation result")]
+    MissingOperationResult,
+    #[error(transparent)]
+    Rpc(#[from] rpc::Error),
+    #[error(transparent)]
+    Wasm(#[from] wasm::Error),
+    #[error(transparent)]
+    Key(#[from] key::Error),
+}
+
+/// The new `live_until_ledger_seq` for a single restored key, paired with the key itself so
+/// callers with more than one `--key`/`--key-xdr` can tell which result belongs to which entry.
+pub struct RestoredKey {
+    pub key: LedgerKey,
+    pub live_until_ledger_seq: u32,
+}
+
+impl Cmd {
+    pub async fn run(&self) -> Result<(), Error> {
+        let restored = self.run_against_rpc_server().await?;
+        for entry in &restored {
+            if self.ttl_ledger_only {
+                println!("{}", entry.live_until_ledger_seq);
+            } else {
+                println!(
+                    "New ttl ledger for {}: {}",
+                    entry.key.to_xdr_base64(Limits::none())?,
+                    entry.live_until_ledger_seq,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn run_against_rpc_server(&self) -> Result<Vec<RestoredKey>, Error> {
+        let keys = self.key.parse_keys()?;
+        self.restore_keys(&keys).await
+    }
+
+    /// Restore exactly `keys`, ignoring the `--key`/`--key-xdr` values in `self.key`. Lets callers (e.g. extend's
+    /// `--restore-if-needed`) restore a subset of a larger `--key` list without having to
+    /// reconstruct a `key::Args`.
+    pub(crate) async fn restore_keys(&self, keys: &[LedgerKey]) -> Result<Vec<RestoredKey>, Error> {
+        let network = self.config.get_network()?;
+        tracing::trace!(?network);
+        let network = &self.config.get_network()?;
+        let client = Client::new(&network.rpc_url)?;
+        let key = self.config.key_pair()?;
+
+        // Get the account sequence number
+        let public_strkey =
+            hcnet_strkey::ed25519::PublicKey(key.verifying_key().to_bytes()).to_string();
+        let account_details = client.get_account(&public_strkey).await?;
+        let sequence: i64 = account_details.seq_num.into();
+
+        let tx = Transaction {
+            source_account: MuxedAccount::Ed25519(Uint256(key.verifying_key().to_bytes())),
+            fee: self.fee.fee,
+            seq_num: SequenceNumber(sequence + 1),
+            cond: Preconditions::None,
+            memo: Memo::None,
+            operations: vec![Operation {
+                source_account: None,
+                body: OperationBody::RestoreFootprint(RestoreFootprintOp {
+                    ext: ExtensionPoint::V0,
+                }),
+            }]
+            .try_into()?,
+            ext: TransactionExt::V1(SorobanTransactionData {
+                ext: ExtensionPoint::V0,
+                resources: SorobanResources {
+                    footprint: LedgerFootprint {
+                        read_only: vec![].try_into()?,
+                        read_write: keys.to_vec().try_into()?,
+                    },
+                    instructions: 0,
+                    read_bytes: 0,
+                    write_bytes: 0,
+                },
+                resource_fee: 0,
+            }),
+        };
+
+        let (result, meta, events) = client
+            .prepare_and_send_transaction(
+                &tx,
+                &key,
+                &[],
+                &network.network_passphrase,
+                None,
+                None,
+                None,
+            )
+            .await?;
+
+        tracing::trace!(?result);
+        tracing::trace!(?meta);
+        if !events.is_empty() {
+            tracing::info!("Events:\n {events:#?}");
+        }
+
+        let TransactionMeta::V3(TransactionMetaV3 { operations, .. }) = meta else {
+            return Err(Error::LedgerEntryNotFound);
+        };
+
+        // As with extend, core succeeds regardless of whether an entry was actually archived, so
+        // we have to inspect the result meta to tell if a restoration actually happened.
+        if operations.is_empty() {
+            return Err(Error::LedgerEntryNotFound);
+        }
+
+        // A key that was already live before this restore produces no change pair at all, so we
+        // can't assume one (State, Updated) pair per key in footprint order; match by the ttl
+        // entry's key hash instead, and fall back to a direct read for whatever's left over.
+        let changed = ttl_changes_by_key_hash(&operations[0].changes).ok_or(Error::LedgerEntryNotFound)?;
+
+        let mut restored = Vec::with_capacity(keys.len());
+        let mut already_live = Vec::new();
+        for key in keys {
+            match changed.get(&ttl_key_hash(key)?) {
+                Some(live_until_ledger_seq) => restored.push(RestoredKey {
+                    key: key.clone(),
+                    live_until_ledger_seq: *live_until_ledger_seq,
+                }),
+                None => already_live.push(key.clone()),
+            }
+        }
+
+        if !already_live.is_empty() {
+            restored.extend(Self::get_unchanged_restorations(&client, &already_live).await?);
+        }
+
+        Ok(restored)
+    }
+
+    /// Reads the current `live_until_ledger_seq` directly for keys the restore didn't touch
+    /// (i.e. they were already live), mirroring `extend`'s fallback for entries core left alone.
+    async fn get_unchanged_restorations(
+        client: &Client,
+        keys: &[LedgerKey],
+    ) -> Result<Vec<RestoredKey>, Error> {
+        let entries = client.get_full_ledger_entries(keys).await?;
+        if entries.entries.len() != keys.len() {
+            return Err(Error::LedgerEntryNotFound);
+        }
+        let mut restored = Vec::with_capacity(keys.len());
+        for (key, entry) in keys.iter().zip(entries.entries.iter()) {
+            if i64::from(entry.live_until_ledger_seq) < entries.latest_ledger {
+                // Still archived: the restore didn't touch this entry at all.
+                return Err(Error::LedgerEntryNotFound);
+            }
+            restored.push(RestoredKey {
+                key: key.clone(),
+                live_until_ledger_seq: entry.live_until_ledger_seq,
+            });
+        }
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use soroban_env_host::xdr::{
+        ContractDataDurability, ContractId, LedgerKeyContractData, ScAddress, ScVal,
+    };
+
+    fn contract_data_key(contract_id: [u8; 32], key: ScVal) -> LedgerKey {
+        LedgerKey::ContractData(LedgerKeyContractData {
+            contract: ScAddress::Contract(ContractId(Hash(contract_id))),
+            key,
+            durability: ContractDataDurability::Persistent,
+        })
+    }
+
+    #[test]
+    fn ttl_changes_by_key_hash_matches_mixed_batches() {
+        let touched = contract_data_key([1; 32], ScVal::U32(1));
+        let untouched = contract_data_key([2; 32], ScVal::U32(2));
+
+        // Core only emits a change pair for the entry it actually restored; `untouched` (e.g.
+        // already live) has no corresponding pair at all.
+        let changes = vec![
+            LedgerEntryChange::State(LedgerEntry {
+                last_modified_ledger_seq: 0,
+                data: LedgerEntryData::Ttl(TtlEntry {
+                    key_hash: ttl_key_hash(&touched).unwrap(),
+                    live_until_ledger_seq: 100,
+                }),
+                ext: soroban_env_host::xdr::LedgerEntryExt::V0,
+            }),
+            LedgerEntryChange::Updated(LedgerEntry {
+                last_modified_ledger_seq: 0,
+                data: LedgerEntryData::Ttl(TtlEntry {
+                    key_hash: ttl_key_hash(&touched).unwrap(),
+                    live_until_ledger_seq: 500,
+                }),
+                ext: soroban_env_host::xdr::LedgerEntryExt::V0,
+            }),
+        ];
+
+        let by_hash = ttl_changes_by_key_hash(&changes).expect("valid change pairs");
+        assert_eq!(by_hash.get(&ttl_key_hash(&touched).unwrap()), Some(&500));
+        assert_eq!(by_hash.get(&ttl_key_hash(&untouched).unwrap()), None);
+    }
+}